@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::UptimeResponse;
+
+type CacheKey = (String, u64);
+
+struct Entry {
+	response: UptimeResponse,
+	inserted_at: Instant,
+}
+
+lazy_static::lazy_static! {
+	static ref CACHE: Mutex<HashMap<CacheKey, Entry>> = Mutex::new(HashMap::new());
+}
+
+/// Returns the cached response for `(domain, t1)` if present and not older
+/// than `max_ttl_secs`. Because `t1` is the end of the bucket the data
+/// belongs to, a cache hit also implicitly guarantees the data is for the
+/// current bucket.
+///
+/// Only the default `days`/`step` window is ever stored here (see
+/// `uptime_inner`'s use of this module) — caching client-chosen
+/// `days`/`step` combinations would let a client force unbounded cache
+/// growth by requesting many distinct windows, so those bypass the cache
+/// entirely.
+pub fn get(domain: &str, t1: u64, max_ttl_secs: u64) -> Option<UptimeResponse> {
+	let cache = CACHE.lock().unwrap();
+	let entry = cache.get(&(domain.to_string(), t1))?;
+	if entry.inserted_at.elapsed().as_secs() > max_ttl_secs {
+		return None;
+	}
+	Some(entry.response.clone())
+}
+
+pub fn put(domain: &str, t1: u64, response: UptimeResponse, max_ttl_secs: u64) {
+	let mut cache = CACHE.lock().unwrap();
+	// Evict every entry that's already past its TTL on each write.
+	cache.retain(|_, entry| entry.inserted_at.elapsed().as_secs() <= max_ttl_secs);
+	cache.insert(
+		(domain.to_string(), t1),
+		Entry {
+			response,
+			inserted_at: Instant::now(),
+		},
+	);
+}
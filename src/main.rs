@@ -1,29 +1,160 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
 use poem::{
 	get, handler,
 	http::StatusCode,
-	listener::TcpListener,
-	web::{Json, Path},
+	listener::{
+		acme::{AutoCert, ChallengeType},
+		Listener, RustlsCertificate, RustlsConfig, TcpListener,
+	},
+	web::{Json, Path, Query},
 	IntoResponse, Route, Server,
 };
 
-use figment::{providers::Env, Figment};
+use figment::{
+	providers::{Env, Format, Yaml},
+	Figment,
+};
 
 use prometheus_http_query::Client;
 
+use tracing::Instrument;
+
+mod cache;
+mod metrics;
+mod telemetry;
+
+use metrics::{
+	ALLOWLIST_REJECTIONS_TOTAL, REQUESTS_BY_DOMAIN, REQUESTS_TOTAL, UPSTREAM_ERRORS_TOTAL,
+	UPSTREAM_QUERY_DURATION,
+};
+
+fn default_job() -> String {
+	"xmppobserve:xmpps?-(client|server)".to_string()
+}
+
+fn default_aggregation() -> String {
+	"max".to_string()
+}
+
+#[derive(Deserialize, Clone)]
+struct DomainConfig {
+	/// PromQL query template, with `{domain}` substituted for the escaped
+	/// domain name.
+	query_template: String,
+	#[serde(default = "default_job")]
+	job: String,
+	#[serde(default = "default_aggregation")]
+	aggregation: String,
+}
+
+fn domain_query_template_default() -> String {
+	"{aggregation}(avg_over_time(probe_success{job=~\"{job}\", domain=\"{domain}\"}[1h])) by (domain)"
+		.to_string()
+}
+
+/// Expands `{placeholder}` tokens in `template` in a single left-to-right
+/// pass, via `lookup`. Unlike chained `String::replace` calls, substituted
+/// text is never re-scanned for further placeholders, so a value that
+/// happens to contain a literal `{job}`/`{aggregation}` token can't corrupt
+/// later substitutions.
+fn substitute_query_template<'a>(
+	template: &str,
+	lookup: impl Fn(&str) -> Option<&'a str>,
+) -> String {
+	let mut out = String::with_capacity(template.len());
+	let mut rest = template;
+	while let Some(first) = rest.find('{') {
+		let Some(end) = rest[first..].find('}') else {
+			out.push_str(rest);
+			return out;
+		};
+		let end = first + end;
+		// Use the innermost `{` before the `}`, so a literal brace such as a
+		// PromQL label matcher (`probe_success{job=~"{job}"}`) doesn't swallow
+		// the placeholder inside it.
+		let start = first + rest[first..end].rfind('{').unwrap_or(0);
+		let placeholder = &rest[start + 1..end];
+		out.push_str(&rest[..start]);
+		match lookup(placeholder) {
+			Some(value) => out.push_str(value),
+			None => out.push_str(&rest[start..=end]),
+		}
+		rest = &rest[end + 1..];
+	}
+	out.push_str(rest);
+	out
+}
+
+/// Error returned by [`query_uptime`]: either the upstream Prometheus call
+/// failed, or the configured `query_template` didn't produce a usable
+/// result (e.g. it returned a scalar instead of a range vector).
+enum QueryError {
+	Upstream(prometheus_http_query::error::Error),
+	InvalidTemplate(String),
+}
+
+impl std::fmt::Display for QueryError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			QueryError::Upstream(e) => write!(f, "{}", e),
+			QueryError::InvalidTemplate(message) => write!(f, "{}", message),
+		}
+	}
+}
+
+impl From<prometheus_http_query::error::Error> for QueryError {
+	fn from(e: prometheus_http_query::error::Error) -> Self {
+		QueryError::Upstream(e)
+	}
+}
+
+impl Default for DomainConfig {
+	fn default() -> Self {
+		Self {
+			query_template: domain_query_template_default(),
+			job: default_job(),
+			aggregation: default_aggregation(),
+		}
+	}
+}
+
 #[derive(Deserialize)]
 struct Config {
 	#[serde(default = "prometheus_url_default")]
 	prometheus_url: String,
 	#[serde(default = "bind_address")]
 	bind_address: String,
-	#[serde(default = "domain_allowlist_default")]
-	domain_allowlist: Vec<String>,
+	#[serde(default = "domains_default")]
+	domains: HashMap<String, DomainConfig>,
+	#[serde(default = "cache_max_ttl_secs_default")]
+	cache_max_ttl_secs: u64,
+	otlp_endpoint: Option<String>,
+	#[serde(default = "max_days_default")]
+	max_days: u64,
+	#[serde(default = "min_step_secs_default")]
+	min_step_secs: u64,
+	#[serde(default = "max_step_secs_default")]
+	max_step_secs: u64,
+	tls_cert_path: Option<String>,
+	tls_key_path: Option<String>,
+	#[serde(default)]
+	acme_enabled: bool,
+	acme_domain: Option<String>,
+	#[serde(default = "acme_cache_dir_default")]
+	acme_cache_dir: String,
 }
 
-#[derive(Serialize)]
-struct UptimeResponse {
+#[derive(Deserialize)]
+struct UptimeQuery {
+	days: Option<u64>,
+	step: Option<u64>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct UptimeResponse {
 	domain: String,
 	t0: u64,
 	uptime_history: Vec<Option<f64>>,
@@ -45,7 +176,11 @@ enum Response {
 
 lazy_static::lazy_static! {
 	static ref CONFIG: Config = {
-		let config: Config = Figment::new()
+		let mut figment = Figment::new();
+		if let Ok(config_path) = std::env::var("UPTIMEPROXY_CONFIG_PATH") {
+			figment = figment.merge(Yaml::file(config_path));
+		}
+		let config: Config = figment
 			.merge(Env::prefixed("UPTIMEPROXY_"))
 			.extract()
 			.expect("invalid configuration");
@@ -61,39 +196,104 @@ fn bind_address() -> String {
 	"127.0.0.1:8080".to_string()
 }
 
-fn domain_allowlist_default() -> Vec<String> {
-	vec![]
+fn domains_default() -> HashMap<String, DomainConfig> {
+	HashMap::new()
+}
+
+fn cache_max_ttl_secs_default() -> u64 {
+	300
 }
 
-async fn query_uptime(domain: &str) -> Result<UptimeResponse, prometheus_http_query::error::Error> {
-	const NDAYS: u64 = 14;
+fn max_days_default() -> u64 {
+	90
+}
 
+fn min_step_secs_default() -> u64 {
+	60
+}
+
+fn max_step_secs_default() -> u64 {
+	86400
+}
+
+fn acme_cache_dir_default() -> String {
+	"./acme-cache".to_string()
+}
+
+#[tracing::instrument(
+	name = "query_range",
+	skip(domain_config),
+	fields(
+		promql = tracing::field::Empty,
+		range.t0 = t0,
+		range.t1 = t1,
+		samples.len = tracing::field::Empty,
+		error = tracing::field::Empty
+	)
+)]
+async fn query_uptime(
+	domain: &str,
+	domain_config: &DomainConfig,
+	t0: u64,
+	t1: u64,
+	step_secs: u64,
+) -> Result<UptimeResponse, QueryError> {
 	let client = Client::try_from(CONFIG.prometheus_url.clone())?;
-	let q = format!(
-		"max(avg_over_time(probe_success{{job=~\"xmppobserve:xmpps?-(client|server)\", domain=\"zombofant.net\"}}[1h])) by (domain)",
-	);
-	let t1 = std::time::SystemTime::now()
-		.duration_since(std::time::SystemTime::UNIX_EPOCH)
-		.unwrap()
-		.as_secs();
-	let t1 = t1 - (t1 % 3600);
-	let t0 = t1 - 3600 * 24 * NDAYS;
+	let escaped_domain = domain.replace('\\', "\\\\").replace('"', "\\\"");
+	let q = substitute_query_template(&domain_config.query_template, |placeholder| {
+		match placeholder {
+			"domain" => Some(escaped_domain.as_str()),
+			"job" => Some(domain_config.job.as_str()),
+			"aggregation" => Some(domain_config.aggregation.as_str()),
+			_ => None,
+		}
+	});
+	tracing::Span::current().record("promql", q.as_str());
 
+	let timer = UPSTREAM_QUERY_DURATION
+		.with_label_values(&[domain])
+		.start_timer();
 	let response = client
-		.query_range(q, t0 as i64, t1 as i64, 3600.0)
+		.query_range(q, t0 as i64, t1 as i64, step_secs as f64)
 		.get()
-		.await?;
-	let series = response.data().as_matrix().expect("matrix result");
-	let mut samples = Vec::new();
-	samples.resize(24 * NDAYS as usize + 1, None);
-	for sample in series[0].samples() {
-		let bucket = ((sample.timestamp() - t0 as f64) as i64) / 3600;
-		if bucket < 0 {
-			continue;
+		.await;
+	timer.observe_duration();
+	let response = match response {
+		Ok(v) => v,
+		Err(e) => {
+			UPSTREAM_ERRORS_TOTAL.inc();
+			tracing::Span::current().record("error", e.to_string().as_str());
+			return Err(e.into());
 		}
-		if let Some(dest) = samples.get_mut(bucket as usize) {
-			*dest = Some(sample.value());
+	};
+	let series = match response.data().as_matrix() {
+		Some(series) => series,
+		None => {
+			UPSTREAM_ERRORS_TOTAL.inc();
+			let message = "query_template did not yield a range-vector result".to_string();
+			tracing::Span::current().record("error", message.as_str());
+			return Err(QueryError::InvalidTemplate(message));
 		}
+	};
+	let n_buckets = ((t1 - t0) / step_secs) as usize + 1;
+	let mut samples = Vec::new();
+	samples.resize(n_buckets, None);
+	// An empty matrix is a valid result (e.g. a freshly-configured domain
+	// with no matching samples yet), not an error — leave `samples` as
+	// all-`None` in that case.
+	if let Some(series) = series.first() {
+		for sample in series.samples() {
+			let bucket = ((sample.timestamp() - t0 as f64) as i64) / step_secs as i64;
+			if bucket < 0 {
+				continue;
+			}
+			if let Some(dest) = samples.get_mut(bucket as usize) {
+				*dest = Some(sample.value());
+			}
+		}
+		tracing::Span::current().record("samples.len", series.samples().len());
+	} else {
+		tracing::Span::current().record("samples.len", 0);
 	}
 	Ok(UptimeResponse {
 		domain: domain.into(),
@@ -103,32 +303,135 @@ async fn query_uptime(domain: &str) -> Result<UptimeResponse, prometheus_http_qu
 }
 
 #[handler]
-async fn uptime(Path(domain): Path<String>) -> (StatusCode, Json<Response>) {
-	if !CONFIG.domain_allowlist.contains(&domain) {
+async fn uptime(
+	Path(domain): Path<String>,
+	Query(query): Query<UptimeQuery>,
+) -> (StatusCode, Json<Response>) {
+	let span = tracing::info_span!("uptime", domain = %domain, error = tracing::field::Empty);
+	uptime_inner(domain, query).instrument(span).await
+}
+
+async fn uptime_inner(domain: String, query: UptimeQuery) -> (StatusCode, Json<Response>) {
+	REQUESTS_TOTAL.inc();
+
+	let domain_config = match CONFIG.domains.get(&domain) {
+		Some(v) => v,
+		None => {
+			ALLOWLIST_REJECTIONS_TOTAL.inc();
+			let message = format!("domain {} is not tracked", domain);
+			tracing::Span::current().record("error", message.as_str());
+			return (
+				StatusCode::NOT_FOUND,
+				Json(Response::Error(ErrorResponse { message })),
+			);
+		}
+	};
+	// Only increment the per-domain label after the allowlist check, so its
+	// cardinality is bounded by CONFIG.domains rather than attacker input.
+	REQUESTS_BY_DOMAIN.with_label_values(&[&domain]).inc();
+
+	const DEFAULT_DAYS: u64 = 14;
+	const DEFAULT_STEP_SECS: u64 = 3600;
+
+	let days = query.days.unwrap_or(DEFAULT_DAYS);
+	let step_secs = query.step.unwrap_or(DEFAULT_STEP_SECS);
+	if days < 1 || days > CONFIG.max_days {
+		let message = format!("days must be between 1 and {}", CONFIG.max_days);
+		tracing::Span::current().record("error", message.as_str());
 		return (
-			StatusCode::NOT_FOUND,
-			Json(Response::Error(ErrorResponse {
-				message: format!("domain {} is not tracked", domain),
-			})),
+			StatusCode::BAD_REQUEST,
+			Json(Response::Error(ErrorResponse { message })),
 		);
 	}
+	if step_secs < CONFIG.min_step_secs || step_secs > CONFIG.max_step_secs {
+		let message = format!(
+			"step must be between {} and {} seconds",
+			CONFIG.min_step_secs, CONFIG.max_step_secs
+		);
+		tracing::Span::current().record("error", message.as_str());
+		return (
+			StatusCode::BAD_REQUEST,
+			Json(Response::Error(ErrorResponse { message })),
+		);
+	}
+
+	let t1 = std::time::SystemTime::now()
+		.duration_since(std::time::SystemTime::UNIX_EPOCH)
+		.unwrap()
+		.as_secs();
+	let t1 = t1 - (t1 % step_secs);
+	let t0 = t1 - days * 86400;
+
+	// Only the default window is cacheable: caching arbitrary client-chosen
+	// days/step combinations would let a client force unbounded cache
+	// growth within a single TTL window by requesting many distinct ones.
+	let cacheable = days == DEFAULT_DAYS && step_secs == DEFAULT_STEP_SECS;
 
-	match query_uptime(&domain).await {
-		Ok(v) => (StatusCode::OK, Json(Response::Success(v))),
-		Err(e) => (
-			StatusCode::INTERNAL_SERVER_ERROR,
-			Json(Response::Error(ErrorResponse {
-				message: e.to_string(),
-			})),
-		),
+	if cacheable {
+		if let Some(cached) = cache::get(&domain, t1, CONFIG.cache_max_ttl_secs) {
+			return (StatusCode::OK, Json(Response::Success(cached)));
+		}
+	}
+
+	match query_uptime(&domain, domain_config, t0, t1, step_secs).await {
+		Ok(v) => {
+			if cacheable {
+				cache::put(&domain, t1, v.clone(), CONFIG.cache_max_ttl_secs);
+			}
+			(StatusCode::OK, Json(Response::Success(v)))
+		}
+		Err(e) => {
+			tracing::Span::current().record("error", e.to_string().as_str());
+			(
+				StatusCode::INTERNAL_SERVER_ERROR,
+				Json(Response::Error(ErrorResponse {
+					message: e.to_string(),
+				})),
+			)
+		}
 	}
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<(dyn std::error::Error + 'static)>> {
-	let app = Route::new().at("/uptime/:domain", get(uptime));
-	Server::new(TcpListener::bind(&CONFIG.bind_address))
-		.run(app)
-		.await?;
+	let tracer = telemetry::init(CONFIG.otlp_endpoint.as_deref())?;
+
+	let app = Route::new()
+		.at("/uptime/:domain", get(uptime))
+		.at("/metrics", get(metrics::metrics));
+
+	if CONFIG.acme_enabled {
+		let domain = CONFIG
+			.acme_domain
+			.as_deref()
+			.expect("acme_domain must be set when acme_enabled is true");
+		let auto_cert = AutoCert::builder()
+			.directory_url("https://acme-v02.api.letsencrypt.org/directory")
+			.domain(domain)
+			.cache_path(&CONFIG.acme_cache_dir)
+			.challenge_type(ChallengeType::TlsAlpn01)
+			.build()?;
+		Server::new(TcpListener::bind(&CONFIG.bind_address).acme(auto_cert))
+			.run(app)
+			.await?;
+	} else if let (Some(cert_path), Some(key_path)) =
+		(CONFIG.tls_cert_path.as_deref(), CONFIG.tls_key_path.as_deref())
+	{
+		let cert = std::fs::read(cert_path)?;
+		let key = std::fs::read(key_path)?;
+		let rustls_config =
+			RustlsConfig::new().fallback(RustlsCertificate::new().cert(cert).key(key));
+		Server::new(TcpListener::bind(&CONFIG.bind_address).rustls(rustls_config))
+			.run(app)
+			.await?;
+	} else {
+		Server::new(TcpListener::bind(&CONFIG.bind_address))
+			.run(app)
+			.await?;
+	}
+
+	if tracer.is_some() {
+		opentelemetry::global::shutdown_tracer_provider();
+	}
 	Ok(())
 }
@@ -0,0 +1,73 @@
+use poem::{handler, http::StatusCode, IntoResponse};
+use prometheus::{
+	Encoder, HistogramVec, IntCounter, IntCounterVec, Registry, TextEncoder,
+};
+
+lazy_static::lazy_static! {
+	pub static ref REGISTRY: Registry = Registry::new();
+
+	pub static ref REQUESTS_TOTAL: IntCounter = {
+		let counter = IntCounter::new(
+			"uptimeproxy_requests_total",
+			"Total number of requests to /uptime/:domain",
+		)
+		.unwrap();
+		REGISTRY.register(Box::new(counter.clone())).unwrap();
+		counter
+	};
+
+	pub static ref REQUESTS_BY_DOMAIN: IntCounterVec = {
+		let counter = IntCounterVec::new(
+			prometheus::Opts::new(
+				"uptimeproxy_requests_by_domain_total",
+				"Total number of requests to /uptime/:domain, by domain",
+			),
+			&["domain"],
+		)
+		.unwrap();
+		REGISTRY.register(Box::new(counter.clone())).unwrap();
+		counter
+	};
+
+	pub static ref ALLOWLIST_REJECTIONS_TOTAL: IntCounter = {
+		let counter = IntCounter::new(
+			"uptimeproxy_allowlist_rejections_total",
+			"Total number of requests rejected because the domain is not tracked",
+		)
+		.unwrap();
+		REGISTRY.register(Box::new(counter.clone())).unwrap();
+		counter
+	};
+
+	pub static ref UPSTREAM_ERRORS_TOTAL: IntCounter = {
+		let counter = IntCounter::new(
+			"uptimeproxy_upstream_errors_total",
+			"Total number of failed upstream Prometheus queries, including results that are not a range vector",
+		)
+		.unwrap();
+		REGISTRY.register(Box::new(counter.clone())).unwrap();
+		counter
+	};
+
+	pub static ref UPSTREAM_QUERY_DURATION: HistogramVec = {
+		let histogram = HistogramVec::new(
+			prometheus::HistogramOpts::new(
+				"uptimeproxy_upstream_query_duration_seconds",
+				"Latency of the upstream query_range call, by domain",
+			),
+			&["domain"],
+		)
+		.unwrap();
+		REGISTRY.register(Box::new(histogram.clone())).unwrap();
+		histogram
+	};
+}
+
+#[handler]
+pub async fn metrics() -> impl IntoResponse {
+	let metric_families = REGISTRY.gather();
+	let encoder = TextEncoder::new();
+	let mut buffer = Vec::new();
+	encoder.encode(&metric_families, &mut buffer).unwrap();
+	(StatusCode::OK, buffer)
+}
@@ -0,0 +1,40 @@
+use opentelemetry::sdk::trace::Tracer;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// Initializes the global `tracing` subscriber, optionally exporting spans to
+/// an OTLP collector when `otlp_endpoint` is set.
+///
+/// Returns the `Tracer` so its provider can be flushed on shutdown; callers
+/// that don't need OTLP get `None` and a subscriber that only logs to
+/// stdout.
+pub fn init(otlp_endpoint: Option<&str>) -> Result<Option<Tracer>, opentelemetry::trace::TraceError> {
+	let env_filter =
+		EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+	let fmt_layer = tracing_subscriber::fmt::layer();
+
+	let tracer = match otlp_endpoint {
+		Some(endpoint) => Some(
+			opentelemetry_otlp::new_pipeline()
+				.tracing()
+				.with_exporter(
+					opentelemetry_otlp::new_exporter()
+						.tonic()
+						.with_endpoint(endpoint),
+				)
+				.install_batch(opentelemetry::runtime::Tokio)?,
+		),
+		None => None,
+	};
+
+	let otel_layer = tracer
+		.clone()
+		.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+
+	tracing_subscriber::registry()
+		.with(env_filter)
+		.with(fmt_layer)
+		.with(otel_layer)
+		.init();
+
+	Ok(tracer)
+}